@@ -1,4 +1,10 @@
 //! Kafka sink for Raw events.
+mod memory;
+
+pub use self::memory::MemoryBroker;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use futures::future::Future;
 use metric;
 use metric::{LogLine, Telemetry};
@@ -8,21 +14,240 @@ use rdkafka::error::{KafkaError, RDKafkaError};
 use rdkafka::message::{Message, OwnedMessage};
 use rdkafka::producer::FutureProducer;
 use rdkafka::producer::future_producer::DeliveryFuture;
+use rand::Rng;
 use sink::Sink;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use util::Valve;
 
+/// Abstraction over a Kafka producer backend so `Kafka` can be unit-tested
+/// without a live broker. Mirrors the subset of
+/// `rdkafka::producer::FutureProducer`'s API that this sink actually uses;
+/// see `MemoryBroker` for an in-process implementation that stores
+/// publishes and can inject transient `RDKafkaError`s.
+pub trait RawProducer {
+    /// The future returned by `send_copy`, resolving the same way
+    /// `rdkafka::producer::future_producer::DeliveryFuture` does: an inner
+    /// `Ok((partition, offset))` on success, or `Err((KafkaError,
+    /// OwnedMessage))` carrying back the original message on failure.
+    type DeliveryFuture: Future<
+        Item = Result<(i32, i64), (KafkaError, OwnedMessage)>,
+        Error = ::futures::Canceled,
+    >;
+
+    /// Publish a copy of `payload`/`key` to `topic`. Mirrors
+    /// `FutureProducer::send_copy`'s signature.
+    fn send_copy(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        payload: Option<&[u8]>,
+        key: Option<&[u8]>,
+        timestamp: Option<i64>,
+        block_ms: i64,
+    ) -> Self::DeliveryFuture;
+}
+
+impl RawProducer for FutureProducer<EmptyContext> {
+    type DeliveryFuture = DeliveryFuture;
+
+    fn send_copy(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        payload: Option<&[u8]>,
+        key: Option<&[u8]>,
+        timestamp: Option<i64>,
+        block_ms: i64,
+    ) -> DeliveryFuture {
+        FutureProducer::send_copy(self, topic, partition, payload, key, timestamp, block_ms)
+    }
+}
+
 lazy_static! {
     /// Total records published.
     pub static ref KAFKA_PUBLISH_SUCCESS_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     /// Total record publish retries.
     pub static ref KAFKA_PUBLISH_RETRY_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
-    /// Total record publish failures.
+    /// Total record publish failures. A failure here means the message was
+    /// unrecoverable *and* could not be redirected to the dead-letter queue,
+    /// i.e. true data loss.
     pub static ref KAFKA_PUBLISH_FAILURE_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     /// Total record publish retry failures. This occurs when the error signal does not include the original message.
     pub static ref KAFKA_PUBLISH_RETRY_FAILURE_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// Total records successfully redirected to the dead-letter queue, either
+    /// the DLQ topic or the on-disk spill file.
+    pub static ref KAFKA_DLQ_SUCCESS_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// Total records that failed delivery to the dead-letter queue. These are
+    /// counted into `KAFKA_PUBLISH_FAILURE_SUM` as well, since the message is
+    /// now truly lost.
+    pub static ref KAFKA_DLQ_FAILURE_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// Total records spilled to the on-disk DLQ segment because no DLQ broker
+    /// was reachable.
+    pub static ref KAFKA_DLQ_SPILL_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// Total uncompressed bytes handed to `deliver_raw`.
+    pub static ref KAFKA_PUBLISH_BYTES_IN_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// Total bytes actually placed on the wire, post-compression. Compare
+    /// against `KAFKA_PUBLISH_BYTES_IN_SUM` for the achieved ratio.
+    pub static ref KAFKA_PUBLISH_BYTES_OUT_SUM: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    /// In-flight bytes per partition, populated only when
+    /// `partition_strategy` is `Modulo`. Lets operators reason about the
+    /// valve per-partition rather than only in aggregate.
+    pub static ref KAFKA_PARTITION_BYTES: Arc<Mutex<HashMap<i32, usize>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// How `deliver_raw` assigns a Kafka partition to each outgoing message.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionStrategy {
+    /// Pass no explicit partition and let librdkafka hash the message key
+    /// on the broker side.
+    Librdkafka,
+    /// Compute the partition directly as `order_by % num_partitions`,
+    /// giving stable, cernan-controlled co-partitioning independent of
+    /// librdkafka's key hash.
+    Modulo,
+}
+
+impl Default for PartitionStrategy {
+    fn default() -> PartitionStrategy {
+        PartitionStrategy::Librdkafka
+    }
+}
+
+/// Single ASCII byte tagging `encoding` into the message key, mirroring how
+/// `Codec::tag` embeds the compression codec. `metric::Encoding` is defined
+/// outside this module, so only the one variant this sink already depends
+/// on elsewhere (`Raw`) is recognized by name; any other variant still
+/// round-trips through the key, but as `b'u'` ("unknown"), since this sink
+/// has no way to name it. See `encoding_from_tag` for the reverse.
+pub(crate) fn encoding_tag(encoding: &metric::Encoding) -> u8 {
+    match *encoding {
+        metric::Encoding::Raw => b'r',
+        _ => b'u',
+    }
+}
+
+/// Recover the `metric::Encoding` tagged by `encoding_tag`, as embedded by
+/// `deliver_raw` into the message key. Returns `None` for `b'u'` or any
+/// unrecognized tag, so a consumer can tell "recognized as non-`Raw`" apart
+/// from "no framing present at all" and decide how loudly to complain
+/// instead of silently mislabeling the payload.
+pub(crate) fn encoding_from_tag(tag: u8) -> Option<metric::Encoding> {
+    match tag {
+        b'r' => Some(metric::Encoding::Raw),
+        _ => None,
+    }
+}
+
+/// Compression codec applied to a raw payload before it is handed to
+/// librdkafka. The chosen codec is tagged onto the message key alongside the
+/// existing `order_by` ordering key so a consuming side can reverse it
+/// without any out-of-band configuration.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// DEFLATE via the `flate2`/`libz-sys` path. Slowest, smallest, and the
+    /// most broadly compatible with downstream consumers.
+    Gzip,
+    /// Fast, moderate ratio.
+    Lz4,
+    /// Fast, moderate ratio, Google's block format.
+    Snappy,
+    /// Slower than `Lz4`/`Snappy` but a materially better ratio.
+    Zstd,
+}
+
+impl Codec {
+    /// Single ASCII byte identifying this codec, embedded in the message key.
+    pub(crate) fn tag(&self) -> u8 {
+        match *self {
+            Codec::Gzip => b'g',
+            Codec::Lz4 => b'4',
+            Codec::Snappy => b's',
+            Codec::Zstd => b'z',
+        }
+    }
+
+    /// Recover the `Codec` that produced a given tag byte, as embedded by
+    /// `deliver_raw` into the message key.
+    pub(crate) fn from_tag(tag: u8) -> Option<Codec> {
+        match tag {
+            b'g' => Some(Codec::Gzip),
+            b'4' => Some(Codec::Lz4),
+            b's' => Some(Codec::Snappy),
+            b'z' => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Reverse `compress`, panicking on failure for the same reason
+    /// `compress` does: a decode failure here means corrupt or
+    /// mis-tagged data, not a transient condition.
+    pub(crate) fn decompress(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Codec::Gzip => {
+                let mut decoder = ::flate2::read::GzDecoder::new(bytes)
+                    .expect("gzip decompression failed");
+                decoder
+                    .read_to_end(&mut out)
+                    .expect("gzip decompression failed");
+            }
+            Codec::Lz4 => {
+                let mut decoder =
+                    ::lz4::Decoder::new(bytes).expect("could not build lz4 decoder");
+                decoder
+                    .read_to_end(&mut out)
+                    .expect("lz4 decompression failed");
+            }
+            Codec::Snappy => {
+                let mut decoder = ::snap::Reader::new(bytes);
+                decoder
+                    .read_to_end(&mut out)
+                    .expect("snappy decompression failed");
+            }
+            Codec::Zstd => {
+                out = ::zstd::decode_all(bytes).expect("zstd decompression failed");
+            }
+        }
+        out
+    }
+
+    /// Compress `bytes`, panicking on failure. Compression failures here
+    /// indicate a broken codec implementation, not a transient condition, so
+    /// there is nothing useful to retry.
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match *self {
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes).expect("gzip compression failed");
+                encoder.finish().expect("gzip compression failed")
+            }
+            Codec::Lz4 => {
+                let mut encoder = ::lz4::EncoderBuilder::new()
+                    .build(Vec::new())
+                    .expect("could not build lz4 encoder");
+                encoder.write_all(bytes).expect("lz4 compression failed");
+                let (buf, result) = encoder.finish();
+                result.expect("lz4 compression failed");
+                buf
+            }
+            Codec::Snappy => {
+                let mut encoder = ::snap::Writer::new(Vec::new());
+                encoder.write_all(bytes).expect("snappy compression failed");
+                encoder.into_inner().expect("snappy compression failed")
+            }
+            Codec::Zstd => ::zstd::encode_all(bytes, 0).expect("zstd compression failed"),
+        }
+    }
 }
 
 /// Config options for Kafka config.
@@ -42,6 +267,32 @@ pub struct KafkaConfig {
     /// How often (seconds) the in-flight messages are checked for delivery.
     /// Default = 1 second
     pub flush_interval: u64,
+    /// Kafka topic to redirect un-retryable deliveries to. If unset, the DLQ
+    /// falls back to `dlq_spill_path`; if that is also unset, lost messages
+    /// are only counted, not retained.
+    pub dlq_topic: Option<String>,
+    /// On-disk path to spill DLQ messages to when `dlq_topic` is unset or its
+    /// broker is unreachable. Messages are appended as length-prefixed
+    /// segments: a little-endian `u32` key length, the key, a little-endian
+    /// `u32` payload length, then the payload.
+    pub dlq_spill_path: Option<String>,
+    /// Compression codec to apply to raw payloads before publishing. Unset
+    /// by default, meaning payloads ship uncompressed as before.
+    pub compression: Option<Codec>,
+    /// Maximum number of times a retryable delivery is resubmitted before it
+    /// is routed to the DLQ (or counted as a failure).
+    pub max_retries: u32,
+    /// Base delay, in milliseconds, for the exponential backoff between
+    /// retry rounds. Doubles with each attempt up to `max_backoff_ms`.
+    pub base_backoff_ms: u64,
+    /// Ceiling, in milliseconds, on the exponential backoff delay.
+    pub max_backoff_ms: u64,
+    /// How `deliver_raw` assigns messages to partitions. Default is
+    /// `Librdkafka`, the prior behavior of always passing `partition: None`.
+    pub partition_strategy: PartitionStrategy,
+    /// Number of partitions to route across. Required when
+    /// `partition_strategy` is `Modulo`; ignored otherwise.
+    pub num_partitions: Option<u32>,
 }
 
 impl Default for KafkaConfig {
@@ -53,18 +304,42 @@ impl Default for KafkaConfig {
             rdkafka_config: None,
             max_message_bytes: 10 * (1 << 20),
             flush_interval: 1,
+            dlq_topic: None,
+            dlq_spill_path: None,
+            compression: None,
+            max_retries: 5,
+            base_backoff_ms: 100,
+            max_backoff_ms: 5_000,
+            partition_strategy: PartitionStrategy::Librdkafka,
+            num_partitions: None,
         }
     }
 }
 
-/// Kafka sink internal state.
-pub struct Kafka {
+/// A single message awaiting Kafka acknowledgement, paired with the number
+/// of times it has already been retried so `flush` can enforce
+/// `KafkaConfig::max_retries` and size its backoff.
+struct InFlight<P: RawProducer> {
+    /// The pending delivery.
+    future: P::DeliveryFuture,
+    /// How many times this message has already been resubmitted.
+    attempt: u32,
+    /// The partition this message was sent to, if `deliver_raw` computed
+    /// one explicitly. Retries reuse it, so a message keeps its
+    /// co-partitioning across retry rounds.
+    partition: Option<i32>,
+}
+
+/// Kafka sink internal state, generic over its producer backend `P` so
+/// tests can substitute `MemoryBroker` for `FutureProducer<EmptyContext>`.
+/// Production use always goes through the default, via `Sink::init`.
+pub struct Kafka<P: RawProducer = FutureProducer<EmptyContext>> {
     /// Name of the stream we are publishing to.
     topic_name: String,
     /// A message producers.
-    producer: FutureProducer<EmptyContext>,
+    producer: P,
     // In-flight messages.
-    messages: Vec<DeliveryFuture>,
+    messages: Vec<InFlight<P>>,
     /// Total byte length of in-flight messages. This is used to open and close
     /// the sink valve.
     message_bytes: usize,
@@ -73,6 +348,29 @@ pub struct Kafka {
     max_message_bytes: usize,
     /// How often (seconds) the in-flight messages are checked for delivery.
     flush_interval: u64,
+    /// A producer dedicated to the dead-letter queue topic, if configured.
+    dlq_producer: Option<P>,
+    /// Name of the DLQ topic we redirect un-retryable deliveries to.
+    dlq_topic: Option<String>,
+    /// In-flight DLQ deliveries.
+    dlq_messages: Vec<P::DeliveryFuture>,
+    /// On-disk segment used when no DLQ broker is reachable.
+    dlq_spill_path: Option<PathBuf>,
+    /// Compression codec applied to raw payloads before publishing, if any.
+    compression: Option<Codec>,
+    /// Maximum number of times a retryable delivery is resubmitted before it
+    /// is routed to the DLQ (or counted as a failure).
+    max_retries: u32,
+    /// Base delay, in milliseconds, for the exponential backoff between
+    /// retry rounds.
+    base_backoff_ms: u64,
+    /// Ceiling, in milliseconds, on the exponential backoff delay.
+    max_backoff_ms: u64,
+    /// How `deliver_raw` assigns messages to partitions.
+    partition_strategy: PartitionStrategy,
+    /// Number of partitions to route across, when `partition_strategy` is
+    /// `Modulo`.
+    num_partitions: Option<u32>,
 }
 
 impl Sink<KafkaConfig> for Kafka {
@@ -90,18 +388,97 @@ impl Sink<KafkaConfig> for Kafka {
                 producer_config.set(key, value);
             }
         }
-        producer_config.set("bootstrap.servers", &config.brokers.unwrap()[..]);
+        producer_config.set("bootstrap.servers", &config.brokers.as_ref().unwrap()[..]);
+
+        let dlq_producer = if config.dlq_topic.is_some() {
+            Some(
+                producer_config
+                    .create::<FutureProducer<_>>()
+                    .expect("could not create Kafka DLQ producer"),
+            )
+        } else {
+            None
+        };
+        let producer = producer_config.create::<FutureProducer<_>>().unwrap();
+
+        Kafka::with_producer(config, producer, dlq_producer)
+    }
+
+    fn valve_state(&self) -> Valve {
+        self.valve_state()
+    }
+
+    fn deliver(&mut self, point: Arc<Option<Telemetry>>) -> () {
+        self.deliver(point)
+    }
+
+    fn deliver_line(&mut self, line: Arc<Option<LogLine>>) -> () {
+        self.deliver_line(line)
+    }
+
+    fn deliver_raw(
+        &mut self,
+        order_by: u64,
+        encoding: metric::Encoding,
+        bytes: Vec<u8>,
+    ) {
+        self.deliver_raw(order_by, encoding, bytes)
+    }
+
+    fn flush(&mut self) {
+        self.flush()
+    }
+
+    fn flush_interval(&self) -> Option<u64> {
+        self.flush_interval()
+    }
+
+    fn shutdown(mut self) -> () {
+        self.shutdown()
+    }
+}
+
+impl<P: RawProducer> Kafka<P> {
+    /// Build a `Kafka<P>` from config plus already-constructed producer
+    /// handles, generic over the producer backend. `init` uses this with
+    /// `FutureProducer`s built from `config.brokers`; tests substitute
+    /// `MemoryBroker` handles to exercise the sink without a live broker.
+    ///
+    /// Panics if `partition_strategy` is `Modulo` without a positive
+    /// `num_partitions`, since `partition_for` would otherwise divide by
+    /// zero or panic on a missing value in the delivery hot path.
+    fn with_producer(config: KafkaConfig, producer: P, dlq_producer: Option<P>) -> Kafka<P> {
+        if config.partition_strategy == PartitionStrategy::Modulo {
+            match config.num_partitions {
+                Some(n) if n > 0 => {}
+                _ => panic!(
+                    "KafkaConfig.num_partitions must be a positive value when \
+                     partition_strategy is Modulo"
+                ),
+            }
+        }
 
         Kafka {
-            topic_name: config.topic_name.unwrap(),
-            producer: producer_config.create::<FutureProducer<_>>().unwrap(),
+            topic_name: config.topic_name.expect("No Kafka topic name provided!"),
+            producer,
             messages: Vec::new(),
             message_bytes: 0,
             max_message_bytes: config.max_message_bytes,
             flush_interval: config.flush_interval,
+            dlq_producer,
+            dlq_topic: config.dlq_topic,
+            dlq_messages: Vec::new(),
+            dlq_spill_path: config.dlq_spill_path.map(PathBuf::from),
+            compression: config.compression,
+            max_retries: config.max_retries,
+            base_backoff_ms: config.base_backoff_ms,
+            max_backoff_ms: config.max_backoff_ms,
+            partition_strategy: config.partition_strategy,
+            num_partitions: config.num_partitions,
         }
     }
 
+    /// Whether the valve should be open or closed, based on in-flight bytes.
     fn valve_state(&self) -> Valve {
         if self.message_bytes < self.max_message_bytes {
             Valve::Open
@@ -120,41 +497,111 @@ impl Sink<KafkaConfig> for Kafka {
 
     /// Fire off the given event to librdkafka. That library handles buffering and
     /// batching internally.
+    ///
+    /// `encoding` is tagged into the key (see `encoding_tag`) alongside the
+    /// optional compression codec, so a consumer such as
+    /// `source::kafka::KafkaSource` can recover it. Only `Raw` is recognized
+    /// by name; any other encoding still round-trips the key but tags as
+    /// "unknown", since this sink has no way to name it -- see
+    /// `encoding_tag`'s doc comment.
     fn deliver_raw(
         &mut self,
         order_by: u64,
-        _encoding: metric::Encoding,
+        encoding: metric::Encoding,
         bytes: Vec<u8>,
     ) {
-        let key = format!("{:X}", order_by);
-        let future = self.try_payload(bytes.as_slice(), key.as_bytes());
-        self.messages.push(future);
-        self.message_bytes += bytes.len();
+        KAFKA_PUBLISH_BYTES_IN_SUM.fetch_add(bytes.len(), Ordering::Relaxed);
+        let enc_tag = encoding_tag(&encoding) as char;
+        let (payload, key) = match self.compression {
+            Some(codec) => (
+                codec.compress(&bytes),
+                format!("{:X}:{}{}", order_by, enc_tag, codec.tag() as char),
+            ),
+            None => (bytes, format!("{:X}:{}", order_by, enc_tag)),
+        };
+        KAFKA_PUBLISH_BYTES_OUT_SUM.fetch_add(payload.len(), Ordering::Relaxed);
+
+        let partition = self.partition_for(order_by);
+        if let Some(partition) = partition {
+            let mut counts = KAFKA_PARTITION_BYTES.lock().unwrap();
+            *counts.entry(partition).or_insert(0) += payload.len();
+        }
+
+        let future = self.try_payload(payload.as_slice(), key.as_bytes(), partition);
+        self.messages.push(InFlight {
+            future,
+            attempt: 0,
+            partition,
+        });
+        self.message_bytes += payload.len();
+    }
+
+    /// Compute the partition `order_by` should be sent to under the
+    /// configured `partition_strategy`. `None` leaves the choice to
+    /// librdkafka's own key hash.
+    fn partition_for(&self, order_by: u64) -> Option<i32> {
+        match self.partition_strategy {
+            PartitionStrategy::Librdkafka => None,
+            PartitionStrategy::Modulo => {
+                // `with_producer` already rejected a zero/missing
+                // `num_partitions` for `Modulo`, so this can't divide by zero.
+                let num_partitions = self.num_partitions.unwrap() as u64;
+                Some((order_by % num_partitions) as i32)
+            }
+        }
     }
 
+    /// Drain and await in-flight messages, resubmitting retryable failures
+    /// with a bounded, jittered exponential backoff between rounds. A
+    /// message that exceeds `max_retries` is routed to the DLQ instead of
+    /// retried again. Bounding the retry count (and sleeping, rather than
+    /// spinning, between rounds) guarantees this returns in finite time even
+    /// against a fully downed broker, so `TimerFlush` ticks and shutdown
+    /// stay responsive.
     fn flush(&mut self) {
         while !self.messages.is_empty() {
-            let retry_payload_and_keys = self.await_inflight_messages();
-            let new_messages = retry_payload_and_keys
-                .iter()
-                .map(|message| {
+            let retries = self.await_inflight_messages();
+            if retries.is_empty() {
+                break;
+            }
+
+            let max_attempt = retries.iter().map(|&(_, attempt, _)| attempt).max().unwrap();
+            thread::sleep(self.backoff_duration(max_attempt));
+
+            self.messages = retries
+                .into_iter()
+                .filter_map(|(message, attempt, partition)| {
                     let payload = message.payload();
                     let key = message.key();
-                    if payload.is_some() && key.is_some() {
-                        Some(self.try_payload(payload.unwrap(), key.unwrap()))
-                    } else {
+                    if payload.is_none() || key.is_none() {
                         error!("Unable to retry message. It was lost to the ether.");
                         KAFKA_PUBLISH_RETRY_FAILURE_SUM
                             .fetch_add(1, Ordering::Relaxed);
-                        None
+                        return None;
+                    }
+                    let next_attempt = attempt + 1;
+                    if next_attempt > self.max_retries {
+                        warn!(
+                            "Kafka message exceeded max_retries ({}); routing to DLQ.",
+                            self.max_retries
+                        );
+                        self.route_to_dlq(&message);
+                        return None;
                     }
+                    let future = self.try_payload(payload.unwrap(), key.unwrap(), partition);
+                    Some(InFlight {
+                        future,
+                        attempt: next_attempt,
+                        partition,
+                    })
                 })
-                .filter(|x| x.is_some())
-                .map(|x| x.unwrap())
                 .collect();
-            self.messages = new_messages;
         }
         self.message_bytes = 0;
+        for count in KAFKA_PARTITION_BYTES.lock().unwrap().values_mut() {
+            *count = 0;
+        }
+        self.drain_dlq();
     }
 
     fn flush_interval(&self) -> Option<u64> {
@@ -162,17 +609,16 @@ impl Sink<KafkaConfig> for Kafka {
     }
 
     fn shutdown(mut self) -> () {
+        // `flush` already drains the DLQ itself once `self.messages` empties.
         self.flush();
     }
-}
 
-impl Kafka {
     /// Send a payload to Kafka and return a future that will resolve to its
     /// delivery result.
-    fn try_payload(&self, payload: &[u8], key: &[u8]) -> DeliveryFuture {
+    fn try_payload(&self, payload: &[u8], key: &[u8], partition: Option<i32>) -> P::DeliveryFuture {
         self.producer.send_copy(
             &self.topic_name[..],
-            /* partition */ None,
+            partition,
             Some(&payload[..]),
             Some(&key[..]),
             /* timestamp */ None,
@@ -180,65 +626,269 @@ impl Kafka {
         )
     }
 
-    /// Wait on all in-flight messages, and return an `OwnedMessage` for each message
-    /// that needs to be retried.
-    fn await_inflight_messages(&mut self) -> Vec<OwnedMessage> {
-        self.messages
-            .iter_mut()
-            .map(|future| {
-                let result = future.wait();
-                match result {
-                    Ok(inner) => match inner {
-                        Ok((_partition, _offset)) => {
-                            KAFKA_PUBLISH_SUCCESS_SUM.fetch_add(1, Ordering::Relaxed);
-                            None
+    /// Wait on all in-flight messages, and return each `OwnedMessage` that
+    /// needs to be retried, paired with its current attempt count and the
+    /// partition it was sent to. Messages that hit an unrecoverable error
+    /// are routed to the DLQ directly; this is done in a second pass, after
+    /// the drain of `self.messages` completes, so the DLQ routing is free
+    /// to borrow `self` again.
+    fn await_inflight_messages(&mut self) -> Vec<(OwnedMessage, u32, Option<i32>)> {
+        let mut to_retry = Vec::new();
+        let mut to_dlq = Vec::new();
+
+        for in_flight in self.messages.drain(..) {
+            let attempt = in_flight.attempt;
+            let partition = in_flight.partition;
+            match in_flight.future.wait() {
+                Ok(Ok((_partition, _offset))) => {
+                    KAFKA_PUBLISH_SUCCESS_SUM.fetch_add(1, Ordering::Relaxed);
+                }
+
+                Ok(Err((err, message))) => match err {
+                    KafkaError::MessageProduction(err) => match err {
+                        RDKafkaError::InvalidMessage
+                        | RDKafkaError::UnknownTopicOrPartition
+                        | RDKafkaError::LeaderNotAvailable
+                        | RDKafkaError::NotLeaderForPartition
+                        | RDKafkaError::RequestTimedOut
+                        | RDKafkaError::NetworkException
+                        | RDKafkaError::GroupLoadInProgress
+                        | RDKafkaError::GroupCoordinatorNotAvailable
+                        | RDKafkaError::NotCoordinatorForGroup
+                        | RDKafkaError::NotEnoughReplicas
+                        | RDKafkaError::NotEnoughReplicasAfterAppend
+                        | RDKafkaError::NotController => {
+                            KAFKA_PUBLISH_RETRY_SUM.fetch_add(1, Ordering::Relaxed);
+                            to_retry.push((message, attempt, partition));
                         }
 
-                        Err((err, message)) => match err {
-                            KafkaError::MessageProduction(err) => match err {
-                                RDKafkaError::InvalidMessage
-                                | RDKafkaError::UnknownTopicOrPartition
-                                | RDKafkaError::LeaderNotAvailable
-                                | RDKafkaError::NotLeaderForPartition
-                                | RDKafkaError::RequestTimedOut
-                                | RDKafkaError::NetworkException
-                                | RDKafkaError::GroupLoadInProgress
-                                | RDKafkaError::GroupCoordinatorNotAvailable
-                                | RDKafkaError::NotCoordinatorForGroup
-                                | RDKafkaError::NotEnoughReplicas
-                                | RDKafkaError::NotEnoughReplicasAfterAppend
-                                | RDKafkaError::NotController => {
-                                    KAFKA_PUBLISH_RETRY_SUM
-                                        .fetch_add(1, Ordering::Relaxed);
-                                    Some(message)
-                                }
-
-                                _ => {
-                                    error!("Kafka broker returned an unrecoverable error: {:?}", err);
-                                    KAFKA_PUBLISH_FAILURE_SUM
-                                        .fetch_add(1, Ordering::Relaxed);
-                                    None
-                                }
-                            },
-
-                            _ => {
-                                error!("Failed in send to kafka broker: {:?}", err);
-                                KAFKA_PUBLISH_FAILURE_SUM
-                                    .fetch_add(1, Ordering::Relaxed);
-                                None
-                            }
-                        },
+                        _ => {
+                            error!("Kafka broker returned an unrecoverable error: {:?}", err);
+                            to_dlq.push(message);
+                        }
                     },
 
                     _ => {
-                        error!("Failed in send to kafka broker: {:?}", result);
+                        error!("Failed in send to kafka broker: {:?}", err);
+                        to_dlq.push(message);
+                    }
+                },
+
+                Err(result) => {
+                    error!("Failed in send to kafka broker: {:?}", result);
+                    KAFKA_PUBLISH_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        for message in &to_dlq {
+            self.route_to_dlq(message);
+        }
+        to_retry
+    }
+
+    /// Compute the delay before the next retry round: exponential backoff
+    /// from `base_backoff_ms`, capped at `max_backoff_ms`, plus uniform
+    /// jitter in `[0, base_backoff_ms)` to avoid a thundering herd of
+    /// resubmissions across sinks retrying in lockstep.
+    fn backoff_duration(&self, attempt: u32) -> Duration {
+        let exponential = self.base_backoff_ms
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::max_value()));
+        let backoff_ms = exponential.min(self.max_backoff_ms);
+        let jitter_ms = if self.base_backoff_ms > 0 {
+            ::rand::thread_rng().gen_range(0, self.base_backoff_ms)
+        } else {
+            0
+        };
+        Duration::from_millis(backoff_ms + jitter_ms)
+    }
+
+    /// Redirect a message that has hit an unrecoverable error to the
+    /// dead-letter queue. Only once the DLQ delivery itself fails is the
+    /// message counted as a true, unrecoverable loss.
+    fn route_to_dlq(&mut self, message: &OwnedMessage) {
+        match (message.payload(), message.key()) {
+            (Some(payload), Some(key)) => {
+                if let Some(ref producer) = self.dlq_producer {
+                    let topic = self.dlq_topic.as_ref().unwrap();
+                    let future = producer.send_copy(
+                        &topic[..],
+                        /* partition */ None,
+                        Some(payload),
+                        Some(key),
+                        /* timestamp */ None,
+                        /* block_ms */ 0,
+                    );
+                    self.dlq_messages.push(future);
+                } else if self.spill_to_disk(payload, key).is_ok() {
+                    KAFKA_DLQ_SUCCESS_SUM.fetch_add(1, Ordering::Relaxed);
+                    KAFKA_DLQ_SPILL_SUM.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    error!("Unable to redirect message to DLQ; message lost.");
+                    KAFKA_DLQ_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+                    KAFKA_PUBLISH_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => {
+                error!("Unable to redirect message to DLQ; payload already lost.");
+                KAFKA_PUBLISH_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Append a `(key, payload)` pair to the on-disk DLQ segment as a pair of
+    /// length-prefixed byte strings, key then payload.
+    fn spill_to_disk(&self, payload: &[u8], key: &[u8]) -> ::std::io::Result<()> {
+        let path = match self.dlq_spill_path {
+            Some(ref path) => path,
+            None => {
+                return Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::NotFound,
+                    "no DLQ spill path configured",
+                ))
+            }
+        };
+        let mut file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&(key.len() as u32).to_le_bytes())?;
+        file.write_all(key)?;
+        file.write_all(&(payload.len() as u32).to_le_bytes())?;
+        file.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Wait on all in-flight DLQ deliveries, counting each toward the DLQ
+    /// success/failure sums. A DLQ delivery is never retried against the DLQ
+    /// topic; a failed one that still carries its message is spilled to
+    /// `dlq_spill_path` instead, and counted as lost only if that fails too.
+    fn drain_dlq(&mut self) {
+        let futures: Vec<P::DeliveryFuture> = self.dlq_messages.drain(..).collect();
+        for future in futures {
+            match future.wait() {
+                Ok(Ok((_partition, _offset))) => {
+                    KAFKA_DLQ_SUCCESS_SUM.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(Err((err, message))) => {
+                    error!("Failed to deliver message to DLQ: {:?}", err);
+                    let spilled = match (message.payload(), message.key()) {
+                        (Some(payload), Some(key)) => self.spill_to_disk(payload, key).is_ok(),
+                        _ => false,
+                    };
+                    if spilled {
+                        KAFKA_DLQ_SUCCESS_SUM.fetch_add(1, Ordering::Relaxed);
+                        KAFKA_DLQ_SPILL_SUM.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        KAFKA_DLQ_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
                         KAFKA_PUBLISH_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
-                        None
                     }
                 }
-            })
-            .filter(|x| x.is_some())
-            .map(|x| x.unwrap())
-            .collect()
+                Err(err) => {
+                    error!("Failed to deliver message to DLQ: {:?}", err);
+                    KAFKA_DLQ_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+                    KAFKA_PUBLISH_FAILURE_SUM.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> KafkaConfig {
+        KafkaConfig {
+            topic_name: Some("test-topic".to_string()),
+            max_retries: 1,
+            base_backoff_ms: 1,
+            max_backoff_ms: 1,
+            ..KafkaConfig::default()
+        }
+    }
+
+    #[test]
+    fn publishes_preserve_delivery_order() {
+        let broker = MemoryBroker::new();
+        let mut kafka = Kafka::with_producer(test_config(), broker.clone(), None);
+
+        kafka.deliver_raw(1, metric::Encoding::Raw, b"one".to_vec());
+        kafka.deliver_raw(2, metric::Encoding::Raw, b"two".to_vec());
+        kafka.deliver_raw(3, metric::Encoding::Raw, b"three".to_vec());
+        kafka.flush();
+
+        let published = broker.published();
+        assert_eq!(published.len(), 3);
+        assert_eq!(published[0].key, Some(b"1:r".to_vec()));
+        assert_eq!(published[0].payload, Some(b"one".to_vec()));
+        assert_eq!(published[1].key, Some(b"2:r".to_vec()));
+        assert_eq!(published[1].payload, Some(b"two".to_vec()));
+        assert_eq!(published[2].key, Some(b"3:r".to_vec()));
+        assert_eq!(published[2].payload, Some(b"three".to_vec()));
+    }
+
+    #[test]
+    fn transient_failure_is_retried_then_succeeds() {
+        let broker = MemoryBroker::new();
+        broker.inject_fault(RDKafkaError::LeaderNotAvailable);
+        let mut kafka = Kafka::with_producer(test_config(), broker.clone(), None);
+
+        kafka.deliver_raw(42, metric::Encoding::Raw, b"payload".to_vec());
+        kafka.flush();
+
+        // A single injected fault only lets this message through if `flush`
+        // resubmitted it after the first failure; asserting on the broker's
+        // own record (rather than the process-global retry counter, which
+        // races with every other test in this module) keeps this
+        // deterministic under parallel test execution.
+        let published = broker.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].payload, Some(b"payload".to_vec()));
+    }
+
+    #[test]
+    fn exhausted_retries_spill_to_dlq() {
+        let broker = MemoryBroker::new();
+        // One more fault than `max_retries` allows, so every attempt fails
+        // and the message is routed to the DLQ instead of succeeding.
+        broker.inject_fault(RDKafkaError::LeaderNotAvailable);
+        broker.inject_fault(RDKafkaError::LeaderNotAvailable);
+
+        let spill_path = ::std::env::temp_dir()
+            .join(format!("cernan-kafka-dlq-test-{}.bin", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&spill_path);
+        let mut config = test_config();
+        config.dlq_spill_path = Some(spill_path.to_str().unwrap().to_string());
+        let mut kafka = Kafka::with_producer(config, broker.clone(), None);
+
+        kafka.deliver_raw(7, metric::Encoding::Raw, b"doomed".to_vec());
+        kafka.flush();
+
+        // Assert on the broker's own record and the spill file this test
+        // uniquely owns, rather than the process-global DLQ counters, which
+        // race with every other test in this module under parallel
+        // execution.
+        assert_eq!(broker.published().len(), 0);
+
+        let mut spilled = Vec::new();
+        File::open(&spill_path)
+            .unwrap()
+            .read_to_end(&mut spilled)
+            .unwrap();
+        let _ = ::std::fs::remove_file(&spill_path);
+        assert!(
+            spilled
+                .windows(b"doomed".len())
+                .any(|window| window == b"doomed")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "num_partitions")]
+    fn modulo_without_partition_count_panics_at_construction() {
+        let broker = MemoryBroker::new();
+        let mut config = test_config();
+        config.partition_strategy = PartitionStrategy::Modulo;
+        config.num_partitions = None;
+
+        Kafka::with_producer(config, broker, None);
     }
 }
\ No newline at end of file
@@ -0,0 +1,30 @@
+//! Event sources: these read from some external store and emit
+//! `server::Event`s into the shared sink channels, the same
+//! `Vec<Sender<Arc<server::Event>>>` produced by `sink::factory`.
+//!
+//! This module must be declared from the crate root (`pub mod source;`,
+//! alongside the existing `pub mod sink;`) and `factory` must be called
+//! from the same place `sink::factory` is, passing through a
+//! `kafka_source: Option<kafka::KafkaSourceConfig>` field added to
+//! `config::Args`, e.g.:
+//!
+//! ```ignore
+//! let sinks = sink::factory(args.clone());
+//! source::factory(args.kafka_source, sinks);
+//! ```
+pub mod kafka;
+
+use server;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+/// Starts whichever sources are configured, each on its own thread, feeding
+/// events into the sink channels produced by `sink::factory`.
+pub fn factory(kafka_source_config: Option<kafka::KafkaSourceConfig>, chans: Vec<Sender<Arc<server::Event>>>) {
+    if let Some(kafka_source_config) = kafka_source_config {
+        thread::spawn(move || {
+            kafka::KafkaSource::new(kafka_source_config).run(chans);
+        });
+    }
+}
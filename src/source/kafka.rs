@@ -0,0 +1,172 @@
+//! Kafka source for replaying captured Raw events back into the pipeline.
+use metric;
+use rdkafka::Message;
+use rdkafka::client::EmptyContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use server;
+use sink::kafka::{Codec, encoding_from_tag};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+
+/// Config options for the Kafka source.
+#[derive(Clone, Debug, Deserialize)]
+pub struct KafkaSourceConfig {
+    /// Canonical name for the given Kafka source.
+    pub config_path: Option<String>,
+    /// Kafka topic to subscribe to.
+    pub topic_name: Option<String>,
+    /// Kafka brokers. This is a comma-separated list of host or host:port.
+    pub brokers: Option<String>,
+    /// Consumer group to join. Required by Kafka for offset tracking.
+    pub consumer_group: Option<String>,
+    /// Underlying librdkafka configuration.
+    pub rdkafka_config: Option<HashMap<String, String>>,
+}
+
+impl Default for KafkaSourceConfig {
+    fn default() -> KafkaSourceConfig {
+        KafkaSourceConfig {
+            config_path: None,
+            topic_name: None,
+            brokers: None,
+            consumer_group: None,
+            rdkafka_config: None,
+        }
+    }
+}
+
+/// Kafka source internal state.
+pub struct KafkaSource {
+    /// Name of the topic we are replaying.
+    topic_name: String,
+    /// The underlying consumer.
+    consumer: StreamConsumer<EmptyContext>,
+}
+
+impl KafkaSource {
+    /// Construct a new `KafkaSource` from its config, panicking the same way
+    /// `sink::kafka::Kafka::init` does when required fields are absent.
+    pub fn new(config: KafkaSourceConfig) -> KafkaSource {
+        if config.topic_name.is_none() {
+            panic!("No Kafka topic name provided!");
+        }
+        if config.brokers.is_none() {
+            panic!("No Kafka brokers provided!")
+        }
+        if config.consumer_group.is_none() {
+            panic!("No Kafka consumer group provided!")
+        }
+
+        let mut consumer_config = ClientConfig::new();
+        if let Some(ref map) = config.rdkafka_config {
+            for (key, value) in map.iter() {
+                consumer_config.set(key, value);
+            }
+        }
+        consumer_config
+            .set("bootstrap.servers", &config.brokers.unwrap()[..])
+            .set("group.id", &config.consumer_group.unwrap()[..])
+            // We commit offsets ourselves, only after the event has been
+            // handed to the downstream channel, so a crash re-reads instead
+            // of silently dropping in-flight messages.
+            .set("enable.auto.commit", "false");
+
+        let topic_name = config.topic_name.unwrap();
+        let consumer = consumer_config
+            .create::<StreamConsumer<_>>()
+            .expect("could not create Kafka consumer");
+        consumer
+            .subscribe(&[&topic_name[..]])
+            .expect("could not subscribe to Kafka topic");
+
+        KafkaSource {
+            topic_name: topic_name,
+            consumer: consumer,
+        }
+    }
+
+    /// Read messages from the subscribed topic forever, reconstructing each
+    /// as a `server::Event::Raw` and handing it to every downstream channel.
+    /// The key written by `sink::kafka::Kafka::deliver_raw` -- the hex
+    /// `order_by`, followed by a `:`, the tagged `metric::Encoding` (see
+    /// `sink::kafka::encoding_tag`), and optionally a trailing codec tag --
+    /// is parsed back out to recover both the encoding and, if present,
+    /// reverse the compression codec before the payload is handed
+    /// downstream.
+    ///
+    /// `sink::kafka::encoding_tag` only recognizes `Raw` by name; any other
+    /// encoding round-trips the key as "unknown" rather than its real
+    /// variant, since the sink has no way to name it. When that happens this
+    /// falls back to emitting the event as `metric::Encoding::Raw` and logs
+    /// a warning, rather than silently mislabeling it with no signal at all.
+    ///
+    /// `StreamConsumer::start` merges messages across all subscribed
+    /// partitions in broker arrival order, so this does not guarantee any
+    /// ordering beyond what each partition itself preserves.
+    ///
+    /// Offsets are committed only after the event has successfully been
+    /// pushed downstream, so a crash mid-read causes Kafka to redeliver
+    /// rather than lose the message.
+    pub fn run(&mut self, chans: Vec<Sender<Arc<server::Event>>>) {
+        for message in self.consumer.start().wait() {
+            let owned = match message {
+                Ok(Ok(msg)) => msg.detach(),
+                Ok(Err(err)) => {
+                    error!("Error while consuming from Kafka topic {}: {:?}", self.topic_name, err);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            let key = owned.key().and_then(|key| ::std::str::from_utf8(key).ok());
+            let (order_by_str, encoding, codec) = match key.and_then(|key| {
+                key.rfind(':').map(|colon| (&key[..colon], &key[colon + 1..]))
+            }) {
+                Some((order_by_str, suffix)) => {
+                    let mut tags = suffix.bytes();
+                    let encoding = match tags.next().and_then(encoding_from_tag) {
+                        Some(encoding) => encoding,
+                        None => {
+                            warn!(
+                                "Kafka message on topic {} carried an unrecognized encoding \
+                                 tag; emitting as Raw.",
+                                self.topic_name
+                            );
+                            metric::Encoding::Raw
+                        }
+                    };
+                    let codec = tags.next().and_then(Codec::from_tag);
+                    (order_by_str, encoding, codec)
+                }
+                None => ("", metric::Encoding::Raw, None),
+            };
+            let order_by = u64::from_str_radix(order_by_str, 16).unwrap_or(0);
+            let payload = match owned.payload() {
+                Some(payload) => match codec {
+                    Some(codec) => codec.decompress(payload),
+                    None => payload.to_vec(),
+                },
+                None => {
+                    error!("Kafka message on topic {} had no payload; skipping.", self.topic_name);
+                    continue;
+                }
+            };
+
+            let event = Arc::new(server::Event::Raw(order_by, encoding, payload));
+            for chan in &chans {
+                chan.send(Arc::clone(&event))
+                    .expect("downstream receiver hung up");
+            }
+
+            if let Err(err) = self.consumer
+                .commit_message(&owned, CommitMode::Async)
+            {
+                error!("Failed to commit Kafka offset on topic {}: {:?}", self.topic_name, err);
+            }
+        }
+    }
+}